@@ -0,0 +1,105 @@
+//! Measures the `i16` fill loop of `next_samples_i16` against the generic `next_samples`.
+//!
+//! Run with `cargo bench --bench fill`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use fbd_sequencer::{OutputMode, PsgTrait, Sequencer, SliceAccessor};
+
+const SAMPLE_RATE: u32 = 44100;
+const SAMPLES: usize = SAMPLE_RATE as usize * 60;
+const CHUNK: usize = 4096;
+const ROUNDS: usize = 5;
+
+/// Square wave PSG, cheap enough for the sequencer to dominate the measurement.
+#[derive(Default)]
+struct SquarePsg {
+    tone_periods: [u16; 3],
+    volumes: [u8; 3],
+    counters: [u16; 3],
+    levels: [bool; 3],
+}
+
+impl PsgTrait for SquarePsg {
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+    fn clock_rate(&self) -> u32 {
+        2_000_000
+    }
+    fn set_tone_period(&mut self, channel: usize, period: u16) {
+        self.tone_periods[channel] = period;
+    }
+    fn set_volume(&mut self, channel: usize, volume: u8) {
+        self.volumes[channel] = volume;
+    }
+    fn set_output_mode(&mut self, _channel: usize, _mode: OutputMode) {}
+    fn set_noise_period(&mut self, _period: u8) {}
+    fn next_sample_i16(&mut self) -> i16 {
+        let mut sample = 0;
+        for channel in 0..3 {
+            self.counters[channel] = self.counters[channel].wrapping_add(3);
+            if self.counters[channel] >= self.tone_periods[channel] {
+                self.counters[channel] = 0;
+                self.levels[channel] = !self.levels[channel];
+            }
+            let level = self.volumes[channel] as i16 * 512;
+            sample += if self.levels[channel] { level } else { -level };
+        }
+        sample
+    }
+    #[cfg(feature = "float")]
+    fn next_sample_f32(&mut self) -> f32 {
+        self.next_sample_i16() as f32 / 32768.0
+    }
+}
+
+/// Renders `SAMPLES` samples with `fill`, returning the elapsed time and the checksum of the
+/// output.
+fn run(fill: fn(&mut fbd_sequencer::PlayContext, &mut [i16]) -> usize) -> (Duration, u64) {
+    let accessor = SliceAccessor::new(include_bytes!("../fbd_files/DRSL400.fbd"));
+    let sequencer = Sequencer::new(&accessor);
+    let mut psg = SquarePsg::default();
+    let mut player = sequencer.play(&mut psg);
+    let mut buffer = [0i16; CHUNK];
+    let mut checksum = 0u64;
+    let mut remaining = SAMPLES;
+    let start = Instant::now();
+    while remaining != 0 {
+        let len = remaining.min(CHUNK);
+        assert_eq!(fill(&mut player, black_box(&mut buffer[..len])), len);
+        checksum = buffer[..len].iter().fold(checksum, |sum, &sample| {
+            sum.wrapping_mul(31).wrapping_add(sample as u64)
+        });
+        remaining -= len;
+    }
+    (start.elapsed(), checksum)
+}
+
+/// Fastest of `ROUNDS` runs, in nanoseconds per sample.
+fn measure(name: &str, fill: fn(&mut fbd_sequencer::PlayContext, &mut [i16]) -> usize) -> u64 {
+    let mut best = Duration::MAX;
+    let mut checksum = 0;
+    for _ in 0..ROUNDS {
+        let (elapsed, sum) = run(fill);
+        best = best.min(elapsed);
+        checksum = sum;
+    }
+    let ns_per_sample = best.as_nanos() as f64 / SAMPLES as f64;
+    println!("{name:<24} {ns_per_sample:>8.2} ns/sample");
+    checksum
+}
+
+fn main() {
+    let specialized = measure("next_samples_i16", |player, buffer| {
+        player.next_samples_i16(buffer)
+    });
+    let generic = measure("next_samples::<i16>", |player, buffer| {
+        player.next_samples::<i16>(buffer)
+    });
+    assert_eq!(
+        specialized, generic,
+        "the fill paths rendered different output"
+    );
+}